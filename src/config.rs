@@ -0,0 +1,150 @@
+use crate::ConnectionManager;
+use crate::CustomizeConnection;
+use crate::Error;
+use crate::NopConnectionCustomizer;
+use crate::Pool;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// The runtime configuration of a `Pool`, built from a `Builder`.
+pub struct Config<E> {
+    pub max_size: u32,
+    pub min_idle: Option<u32>,
+    pub connection_timeout: Duration,
+    pub max_lifetime: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub reaper_rate: Duration,
+    pub test_on_check_out: bool,
+    pub executor: E,
+}
+
+/// A builder for a connection `Pool`.
+pub struct Builder<M> {
+    max_size: u32,
+    min_idle: Option<u32>,
+    connection_timeout: Duration,
+    max_lifetime: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    reaper_rate: Duration,
+    test_on_check_out: bool,
+    connection_customizer: Box<dyn CustomizeConnection<M::Connection, M::Error>>,
+    _keep: PhantomData<M>,
+}
+
+impl<M> Builder<M>
+where
+    M: ConnectionManager,
+{
+    /// Constructs a new `Builder`.
+    ///
+    /// Parameters are initialized with their default values.
+    pub fn new() -> Builder<M> {
+        Builder {
+            max_size: 10,
+            min_idle: None,
+            connection_timeout: Duration::from_secs(30),
+            max_lifetime: Some(Duration::from_secs(30 * 60)),
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+            reaper_rate: Duration::from_secs(30),
+            test_on_check_out: false,
+            connection_customizer: Box::new(NopConnectionCustomizer),
+            _keep: PhantomData,
+        }
+    }
+
+    /// Sets the maximum number of connections managed by the pool.
+    ///
+    /// Defaults to 10.
+    pub fn max_size(mut self, max_size: u32) -> Builder<M> {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Sets the minimum idle connection count maintained by the pool.
+    ///
+    /// If set, the pool will try to maintain at least this many idle
+    /// connections at all times, while respecting `max_size`. Defaults to
+    /// `None` (equivalent to `max_size`).
+    pub fn min_idle(mut self, min_idle: Option<u32>) -> Builder<M> {
+        self.min_idle = min_idle;
+        self
+    }
+
+    /// Sets the maximum time to wait when acquiring a connection.
+    ///
+    /// Defaults to 30 seconds.
+    pub fn connection_timeout(mut self, connection_timeout: Duration) -> Builder<M> {
+        self.connection_timeout = connection_timeout;
+        self
+    }
+
+    /// Sets the maximum lifetime of a connection.
+    ///
+    /// Connections older than this will be closed by the reaper the next time
+    /// they are found idle, or discarded at checkout. `None` disables the
+    /// limit. Defaults to 30 minutes.
+    pub fn max_lifetime(mut self, max_lifetime: Option<Duration>) -> Builder<M> {
+        self.max_lifetime = max_lifetime;
+        self
+    }
+
+    /// Sets the idle timeout of a connection.
+    ///
+    /// Connections that have been idle for longer than this will be closed by
+    /// the reaper. `None` disables the limit. Defaults to 10 minutes.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Builder<M> {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets the interval at which the reaper checks for expired connections.
+    ///
+    /// Defaults to 30 seconds.
+    pub fn reaper_rate(mut self, reaper_rate: Duration) -> Builder<M> {
+        self.reaper_rate = reaper_rate;
+        self
+    }
+
+    /// Sets whether connections are validated with `is_valid` before being
+    /// handed out.
+    ///
+    /// When enabled, a connection that fails validation is discarded and the
+    /// next idle connection is tried. Defaults to `false`.
+    pub fn test_on_check_out(mut self, test_on_check_out: bool) -> Builder<M> {
+        self.test_on_check_out = test_on_check_out;
+        self
+    }
+
+    /// Sets the connection customizer run when connections are acquired and
+    /// released.
+    ///
+    /// Defaults to a no-op customizer.
+    pub fn connection_customizer(
+        mut self,
+        connection_customizer: Box<dyn CustomizeConnection<M::Connection, M::Error>>,
+    ) -> Builder<M> {
+        self.connection_customizer = connection_customizer;
+        self
+    }
+
+    /// Consumes the `Builder`, returning a new, initialized `Pool`.
+    pub async fn build<E>(self, manager: M) -> Result<Pool<M>, Error<E>>
+    where
+        Error<E>: std::convert::From<<M as ConnectionManager>::Error>,
+    {
+        let config = Config {
+            max_size: self.max_size,
+            min_idle: self.min_idle,
+            connection_timeout: self.connection_timeout,
+            max_lifetime: self.max_lifetime,
+            idle_timeout: self.idle_timeout,
+            reaper_rate: self.reaper_rate,
+            test_on_check_out: self.test_on_check_out,
+            executor: manager.get_executor(),
+        };
+
+        let pool = Pool::new_inner(config, manager, self.connection_customizer);
+        pool.wait_for_initialization().await?;
+        Ok(pool)
+    }
+}
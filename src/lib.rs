@@ -2,26 +2,29 @@ mod config;
 
 use config::Builder;
 use config::Config;
+use futures::channel::oneshot;
 pub use futures;
-pub use futures::compat::Future01CompatExt;
-pub use futures::compat::Stream01CompatExt;
 pub use futures::Future;
 pub use futures::FutureExt;
 use std::error;
 use std::fmt;
 use std::marker::Unpin;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::{Mutex, MutexGuard};
+use std::collections::VecDeque;
 use std::time::{Duration, Instant};
-use tokio_executor::Executor as TkExecutor;
 
 static CONNECTION_ID: AtomicUsize = AtomicUsize::new(0);
+static WAITER_ID: AtomicU64 = AtomicU64::new(0);
 
 pub enum Error<E> {
     Inner(E),
-    Timeout,
+    /// Timed out while waiting for a connection. If a background connect failed
+    /// while we were waiting, its error message is carried here for context.
+    Timeout(Option<String>),
 }
 
 impl<E> From<E> for Error<E> {
@@ -30,14 +33,69 @@ impl<E> From<E> for Error<E> {
     }
 }
 
-pub trait Executor: TkExecutor + Send + Sync + 'static + Clone {}
+/// Errors returned by [`Pool::add`].
+#[derive(Debug)]
+pub enum AddError<C> {
+    /// The pool was already at `max_size`; the connection is handed back.
+    PoolFull(C),
+    /// The connection was already broken; it is handed back.
+    Broken(C),
+}
+
+/// A runtime-agnostic handle used to spawn the pool's background connect and
+/// reap tasks.
+///
+/// Implement this for any runtime; the crate ships adapters for Tokio and
+/// async-std behind the `tokio` and `async-std` features.
+pub trait Executor: Send + Sync + 'static + Clone {
+    /// Spawns a future onto the runtime.
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>);
+
+    /// Returns a future that completes after `duration` has elapsed.
+    ///
+    /// Used to drive the checkout timeout and the reaper interval without
+    /// depending on a specific runtime's timer.
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// An `Executor` that spawns onto the Tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioExecutor;
+
+#[cfg(feature = "tokio")]
+impl Executor for TokioExecutor {
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        tokio::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// An `Executor` that spawns onto the async-std runtime.
+#[cfg(feature = "async-std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStdExecutor;
+
+#[cfg(feature = "async-std")]
+impl Executor for AsyncStdExecutor {
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        async_std::task::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async_std::task::sleep(duration))
+    }
+}
 
 pub type AnyFuture<T, E> = Box<dyn Future<Output = Result<T, E>> + Unpin + Send>;
 
 pub trait ConnectionManager: Send + Sync + 'static {
     type Connection: Send + 'static;
     type Error: error::Error + Send + 'static;
-    type Executor: TkExecutor + Send + Sync + 'static + Clone;
+    type Executor: Executor;
 
     fn get_executor(&self) -> Self::Executor;
     fn connect(&self) -> AnyFuture<Self::Connection, Self::Error>;
@@ -45,6 +103,34 @@ pub trait ConnectionManager: Send + Sync + 'static {
     fn has_broken(&self, conn: &mut Self::Connection) -> bool;
 }
 
+/// A trait that lets users run custom logic when a connection is acquired
+/// from the manager or released back to the pool.
+///
+/// The default implementations are no-ops, so implementors only need to
+/// override the hooks they care about.
+pub trait CustomizeConnection<C, E>: Send + Sync + 'static {
+    /// Called with a newly established connection before it enters the pool.
+    ///
+    /// Returning an error causes the connection to be treated as failed and
+    /// re-established with backoff.
+    fn on_acquire<'a>(
+        &'a self,
+        _conn: &'a mut C,
+    ) -> Pin<Box<dyn Future<Output = Result<(), E>> + Send + 'a>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Called with a connection just before it is returned to the pool, either
+    /// to the idle list or directly to a caller waiting for a connection.
+    fn on_release(&self, _conn: &mut C) {}
+}
+
+/// The default `CustomizeConnection`, which does nothing.
+#[derive(Debug, Default)]
+pub struct NopConnectionCustomizer;
+
+impl<C, E> CustomizeConnection<C, E> for NopConnectionCustomizer {}
+
 struct Conn<C> {
     raw: Option<C>,
     id: u64,
@@ -58,6 +144,7 @@ struct IdleConn<C> {
 
 struct PoolInternals<C> {
     conns: Vec<IdleConn<C>>,
+    waiters: VecDeque<(u64, oneshot::Sender<Conn<C>>)>,
     num_conns: u32,
     pending_conns: u32,
     last_error: Option<String>,
@@ -69,7 +156,25 @@ where
 {
     config: Config<M::Executor>,
     manager: M,
+    customizer: Box<dyn CustomizeConnection<M::Connection, M::Error>>,
     internals: Mutex<PoolInternals<M::Connection>>,
+    gets: AtomicU64,
+    gets_with_contention: AtomicU64,
+}
+
+/// Information about the state of a `Pool`.
+#[derive(Debug)]
+pub struct State {
+    /// The number of connections currently being managed by the pool.
+    pub connections: u32,
+    /// The number of idle connections.
+    pub idle_connections: u32,
+    /// The cumulative number of `get`/`get_timeout` calls, including those
+    /// that timed out.
+    pub gets: u64,
+    /// The cumulative number of checkouts that had to wait for a connection
+    /// to become available.
+    pub gets_with_contention: u64,
 }
 
 /// A generic connection pool.
@@ -104,9 +209,14 @@ where
         Builder::new()
     }
 
-    pub fn new_inner(config: Config<M::Executor>, manager: M) -> Pool<M> {
+    pub fn new_inner(
+        config: Config<M::Executor>,
+        manager: M,
+        customizer: Box<dyn CustomizeConnection<M::Connection, M::Error>>,
+    ) -> Pool<M> {
         let internals = PoolInternals {
             conns: Vec::with_capacity(config.max_size as usize),
+            waiters: VecDeque::new(),
             num_conns: 0,
             pending_conns: 0,
             last_error: None,
@@ -115,10 +225,14 @@ where
         let shared = Arc::new(SharedPool {
             config: config,
             manager: manager,
+            customizer: customizer,
             internals: Mutex::new(internals),
+            gets: AtomicU64::new(0),
+            gets_with_contention: AtomicU64::new(0),
         });
 
         establish_idle_connections(&shared, &mut shared.internals.lock().unwrap());
+        schedule_reaping(&shared);
 
         Pool(shared)
     }
@@ -144,37 +258,172 @@ where
     {
         let start = Instant::now();
         let end = start + timeout;
+        self.0.gets.fetch_add(1, Ordering::Relaxed);
+        let mut contended = false;
 
         loop {
-            match self.try_get_inner().await {
-                Ok(conn) => {
-                    return Ok(conn);
-                }
-                Err(_) => (),
-            }
-            {
+            let (waiter_id, rx) = {
                 let mut internals = self.0.internals.lock().unwrap();
+                if let Some(conn) = internals.conns.pop() {
+                    establish_idle_connections(&self.0, &mut internals);
+                    drop(internals);
+
+                    // Apply the checkout checks to the idle connection; a
+                    // discarded connection sends us back to the top of the loop.
+                    match self.prepare_checkout(conn.conn).await {
+                        Some(conn) => return Ok(self.wrap_conn(conn)),
+                        None => continue,
+                    }
+                }
+
+                // Nothing idle: record the contention the first time we miss,
+                // register a waiter at the back of the queue and try to grow
+                // the pool, then wait to be handed a connection.
+                if !contended {
+                    contended = true;
+                    self.0.gets_with_contention.fetch_add(1, Ordering::Relaxed);
+                }
+                let waiter_id = WAITER_ID.fetch_add(1, Ordering::Relaxed);
+                let (tx, rx) = oneshot::channel();
+                internals.waiters.push_back((waiter_id, tx));
                 add_connection(&self.0, &mut internals);
+                (waiter_id, rx)
+            };
+
+            let wait = match end.checked_duration_since(Instant::now()) {
+                Some(wait) => wait,
+                None => return Err(self.deregister_and_timeout(waiter_id, rx)),
+            };
+
+            let sleep = self.0.config.executor.sleep(wait);
+            match futures::future::select(rx, sleep).await {
+                futures::future::Either::Left((res, _sleep)) => match res {
+                    // Handed a connection directly by a return or fresh connect;
+                    // it must pass the same checkout checks as an idle one.
+                    Ok(conn) => match self.prepare_checkout(conn).await {
+                        Some(conn) => return Ok(self.wrap_conn(conn)),
+                        None => continue,
+                    },
+                    // The sender was dropped without delivering; retry.
+                    Err(_canceled) => continue,
+                },
+                // Timed out waiting for a connection.
+                futures::future::Either::Right((_elapsed, rx)) => {
+                    return Err(self.deregister_and_timeout(waiter_id, rx));
+                }
             }
-            
         }
     }
 
-    async fn try_get_inner(&self) -> Result<PooledConnection<M>, ()> {
-        loop {
-            let mut internals = self.0.internals.lock().unwrap();
-            if let Some(mut conn) = internals.conns.pop() {
-                establish_idle_connections(&self.0, &mut internals);
-                drop(internals);
-
-                return Ok(PooledConnection {
-                    pool: self.clone(),
-                    conn: conn.conn,
-                });
-            } else {
-                return Err(());
+    /// Applies the configured checkout checks to a connection before it is
+    /// handed out, regardless of whether it came from the idle list or was
+    /// delivered to a waiter.
+    ///
+    /// Returns `Some` with a usable connection, or `None` if the connection was
+    /// discarded — in which case `num_conns` has already been decremented and
+    /// the pool refilled, and the caller should try again.
+    async fn prepare_checkout(&self, mut conn: Conn<M::Connection>) -> Option<Conn<M::Connection>> {
+        if let Some(max_lifetime) = self.0.config.max_lifetime {
+            if Instant::now().duration_since(conn.birth) >= max_lifetime {
+                self.discard_conn();
+                return None;
             }
         }
+
+        // Optionally validate the connection; a dead socket is discarded so the
+        // caller reaches for another connection instead of handing it out.
+        if self.0.config.test_on_check_out {
+            let raw = conn.raw.take().unwrap();
+            match self.0.manager.is_valid(raw).await {
+                Ok(raw) => conn.raw = Some(raw),
+                Err(_) => {
+                    self.discard_conn();
+                    return None;
+                }
+            }
+        }
+
+        Some(conn)
+    }
+
+    /// Drops a checked-out connection from the pool's accounting and tops the
+    /// idle pool back up to `min_idle`.
+    fn discard_conn(&self) {
+        let mut internals = self.0.internals.lock().unwrap();
+        internals.num_conns -= 1;
+        establish_idle_connections(&self.0, &mut internals);
+    }
+
+    fn wrap_conn(&self, conn: Conn<M::Connection>) -> PooledConnection<M> {
+        PooledConnection {
+            pool: self.clone(),
+            conn,
+        }
+    }
+
+    /// Removes the waiter's sender from the queue and builds a timeout error.
+    ///
+    /// Deregistering keeps `put_idle_conn` from handing a connection to a dead
+    /// receiver, which would otherwise drift `num_conns` upward. A concurrent
+    /// return may still have delivered a connection to our sender between the
+    /// timer firing and us acquiring the lock, so we drain the receiver while
+    /// holding the lock and put any recovered connection back into the pool
+    /// rather than dropping it.
+    fn deregister_and_timeout<E>(
+        &self,
+        waiter_id: u64,
+        mut rx: oneshot::Receiver<Conn<M::Connection>>,
+    ) -> Error<E> {
+        let mut internals = self.0.internals.lock().unwrap();
+        internals.waiters.retain(|(id, _)| *id != waiter_id);
+        if let Ok(Some(conn)) = rx.try_recv() {
+            put_idle_conn(&mut internals, conn);
+        }
+        self.timeout_error(&internals)
+    }
+
+    fn timeout_error<E>(&self, internals: &PoolInternals<M::Connection>) -> Error<E> {
+        Error::Timeout(internals.last_error.clone())
+    }
+
+    /// Adds an externally created connection to the pool.
+    ///
+    /// This lets callers warm the pool with connections established outside the
+    /// manager (e.g. from a failover handshake). The connection is wrapped as
+    /// an idle connection and, if a waiter is pending, handed to it directly.
+    pub fn add(&self, mut conn: M::Connection) -> Result<(), AddError<M::Connection>> {
+        let mut internals = self.0.internals.lock().unwrap();
+
+        if internals.num_conns + internals.pending_conns >= self.0.config.max_size {
+            return Err(AddError::PoolFull(conn));
+        }
+
+        if self.0.manager.has_broken(&mut conn) {
+            return Err(AddError::Broken(conn));
+        }
+
+        let id = CONNECTION_ID.fetch_add(1, Ordering::Relaxed) as u64;
+        let now = Instant::now();
+        let conn = Conn {
+            raw: Some(conn),
+            id,
+            birth: now,
+        };
+
+        internals.num_conns += 1;
+        put_idle_conn(&mut internals, conn);
+        Ok(())
+    }
+
+    /// Returns information about the current state of the pool.
+    pub fn state(&self) -> State {
+        let internals = self.0.internals.lock().unwrap();
+        State {
+            connections: internals.num_conns,
+            idle_connections: internals.conns.len() as u32,
+            gets: self.0.gets.load(Ordering::Relaxed),
+            gets_with_contention: self.0.gets_with_contention.load(Ordering::Relaxed),
+        }
     }
 
     async fn wait_for_initialization<E>(&self) -> Result<(), Error<E>>
@@ -185,6 +434,76 @@ where
     }
 }
 
+/// Hands `conn` to the oldest pending waiter, or stores it as an idle
+/// connection if there is no one waiting.
+fn put_idle_conn<C>(internals: &mut PoolInternals<C>, mut conn: Conn<C>) {
+    while let Some((_id, tx)) = internals.waiters.pop_front() {
+        match tx.send(conn) {
+            Ok(()) => return,
+            // The waiter gave up (timed out); try the next one.
+            Err(returned) => conn = returned,
+        }
+    }
+
+    internals.conns.push(IdleConn {
+        conn,
+        idle_start: Instant::now(),
+    });
+}
+
+/// Spawns a background task that periodically closes connections that have
+/// exceeded `max_lifetime` or `idle_timeout`, refilling the pool afterwards.
+///
+/// The task holds only a `Weak` reference to the shared pool so that it
+/// terminates once the last `Pool` handle is dropped.
+fn schedule_reaping<M>(shared: &Arc<SharedPool<M>>)
+where
+    M: ConnectionManager,
+{
+    if shared.config.max_lifetime.is_none() && shared.config.idle_timeout.is_none() {
+        return;
+    }
+
+    let weak_shared = Arc::downgrade(shared);
+    let rate = shared.config.reaper_rate;
+    let executor = shared.config.executor.clone();
+    shared.config.executor.spawn(Box::pin(async move {
+        loop {
+            executor.sleep(rate).await;
+            let shared = match weak_shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            let mut internals = shared.internals.lock().unwrap();
+            reap_connections(&shared, &mut internals);
+        }
+    }));
+}
+
+fn reap_connections<M>(
+    shared: &Arc<SharedPool<M>>,
+    internals: &mut PoolInternals<M::Connection>,
+) where
+    M: ConnectionManager,
+{
+    let now = Instant::now();
+    let config = &shared.config;
+    let before = internals.conns.len();
+    internals.conns.retain(|idle| {
+        let too_old = config
+            .max_lifetime
+            .map_or(false, |lifetime| now.duration_since(idle.conn.birth) >= lifetime);
+        let too_idle = config
+            .idle_timeout
+            .map_or(false, |timeout| now.duration_since(idle.idle_start) >= timeout);
+        !too_old && !too_idle
+    });
+
+    let reaped = (before - internals.conns.len()) as u32;
+    internals.num_conns -= reaped;
+    establish_idle_connections(shared, internals);
+}
+
 fn establish_idle_connections<M>(
     shared: &Arc<SharedPool<M>>,
     internals: &mut PoolInternals<M::Connection>,
@@ -214,7 +533,11 @@ where
         M: ConnectionManager,
     {
         let new_shared = Arc::downgrade(shared);
-        shared.config.executor.clone().spawn(Box::pin(async move {
+        let executor = shared.config.executor.clone();
+        shared.config.executor.spawn(Box::pin(async move {
+            if delay > Duration::from_secs(0) {
+                executor.sleep(delay).await;
+            }
             let shared = match new_shared.upgrade() {
                 Some(shared) => shared,
                 None => return,
@@ -222,24 +545,29 @@ where
 
             let conn = shared.manager.connect().await;
             match conn {
-                Ok(conn) => {
+                Ok(mut conn) => {
+                    // Let the customizer run its on-acquire setup; a failure
+                    // here is treated like a connect failure and retried.
+                    if let Err(err) = shared.customizer.on_acquire(&mut conn).await {
+                        shared.internals.lock().unwrap().last_error = Some(err.to_string());
+                        let delay = Duration::from_millis(200);
+                        inner(delay, &shared);
+                        return;
+                    }
+
                     let id = CONNECTION_ID.fetch_add(1, Ordering::Relaxed) as u64;
                     let mut internals = shared.internals.lock().unwrap();
                     internals.last_error = None;
                     let now = Instant::now();
-                    let conn = IdleConn {
-                        conn: Conn {
-                            raw: Some(conn),
-                            birth: now,
-                            id,
-                        },
-                        idle_start: now,
+                    let conn = Conn {
+                        raw: Some(conn),
+                        birth: now,
+                        id,
                     };
 
-                    internals.conns.push(conn);
                     internals.pending_conns -= 1;
                     internals.num_conns += 1;
-                    // todo notify the wait
+                    put_idle_conn(&mut internals, conn);
                 }
                 Err(err) => {
                     shared.internals.lock().unwrap().last_error = Some(err.to_string());
@@ -277,7 +605,26 @@ where
     M: ConnectionManager,
 {
     fn drop(&mut self) {
-        println!("drop2");
+        let mut internals = self.pool.0.internals.lock().unwrap();
+        let broken = match self.conn.raw {
+            Some(ref mut raw) => self.pool.0.manager.has_broken(raw),
+            None => true,
+        };
+
+        if broken {
+            internals.num_conns -= 1;
+            establish_idle_connections(&self.pool.0, &mut internals);
+        } else {
+            if let Some(ref mut raw) = self.conn.raw {
+                self.pool.0.customizer.on_release(raw);
+            }
+            let conn = Conn {
+                raw: self.conn.raw.take(),
+                id: self.conn.id,
+                birth: self.conn.birth,
+            };
+            put_idle_conn(&mut internals, conn);
+        }
     }
 }
 